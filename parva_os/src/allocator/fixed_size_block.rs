@@ -0,0 +1,105 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+// Power-of-two block sizes. Must all be a multiple of `mem::align_of::<ListNode>()` so a
+// freed block can always be reused as a `ListNode`.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+// Wraps a non-`Sync` allocator in a spinlock so it can back `#[global_allocator]`.
+pub struct Locked<A> {
+    inner: Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked { inner: Mutex::new(inner) }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+// One free list per entry in `BLOCK_SIZES`, with the linked-list allocator as a fallback
+// for requests too large for any block size.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback: Heap,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback: Heap::empty(),
+        }
+    }
+
+    /// # Safety
+    /// `heap_start` and `heap_size` must describe a valid, unused, and unaliased memory
+    /// range, as required by `linked_list_allocator::Heap::init`.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback.init(heap_start as *mut u8, heap_size);
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback.allocate_first_fit(layout).map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+}
+
+// Index of the smallest block size able to hold `layout`, if any fits.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(index) => match allocator.list_heads[index].take() {
+                Some(node) => {
+                    allocator.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // No block of this size free yet: carve one out of the fallback
+                    // allocator, sized and aligned to this block size.
+                    let block_size = BLOCK_SIZES[index];
+                    let block_align = block_size;
+                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                    allocator.fallback_alloc(layout)
+                }
+            },
+            None => allocator.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode { next: allocator.list_heads[index].take() };
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => {
+                if let Some(ptr) = NonNull::new(ptr) {
+                    allocator.fallback.deallocate(ptr, layout);
+                }
+            }
+        }
+    }
+}
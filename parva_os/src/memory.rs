@@ -1,12 +1,26 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use bootloader::bootinfo::{BootInfo, MemoryMap, MemoryRegionType};
-use x86_64::structures::paging::mapper::MapperAllSizes;
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::mapper::{MapToError, MapperAllSizes, MapperFlush, UnmapError};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
 use crate::print;
 
 // NOTE: This static is mutable but it'll be changed only once during initialization
 static mut PHYS_MEM_OFFSET: u64 = 0;
 
+// Total physical memory detected at boot, in bytes.
+static MEMORY_SIZE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // Built once in `init` from CR3 and reused from then on, rather than re-derived from
+    // CR3 on every call, which would hand out aliased `&'static mut PageTable`s.
+    static ref MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+    static ref FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+}
+
 pub fn init(boot_info: &'static BootInfo) {
     let mut memory_size = 0;
     for region in boot_info.memory_map.iter() {
@@ -16,21 +30,106 @@ pub fn init(boot_info: &'static BootInfo) {
         print!("MEM [{:#016X}-{:#016X}] {:?}\n", start_addr, end_addr, region.region_type);
     }
     print!("MEM {} KB\n", memory_size >> 10);
+    MEMORY_SIZE.store(memory_size, Ordering::SeqCst);
 
     unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset; }
 
-    let mut mapper = unsafe { mapper(VirtAddr::new(PHYS_MEM_OFFSET)) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    crate::allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    // Frame-counting and heap init touch shared state the allocator and page fault
+    // handler both read; keep it consistent if an interrupt fires mid-allocation.
+    without_interrupts(|| {
+        *MAPPER.lock() = Some(unsafe { mapper(VirtAddr::new(PHYS_MEM_OFFSET)) });
+        *FRAME_ALLOCATOR.lock() = Some(unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) });
+
+        crate::allocator::init_heap(
+            MAPPER.lock().as_mut().expect("mapper not initialized"),
+            FRAME_ALLOCATOR.lock().as_mut().expect("frame allocator not initialized"),
+        ).expect("heap initialization failed");
+    });
+}
+
+// Total physical memory detected at boot, in bytes.
+pub fn memory_size() -> u64 {
+    MEMORY_SIZE.load(Ordering::SeqCst)
+}
+
+// Physical memory currently handed out to frames, in bytes.
+pub fn used_memory() -> u64 {
+    let frame_count = FRAME_ALLOCATOR.lock().as_ref().map_or(0, |allocator| allocator.frame_count());
+    (frame_count * 4096) as u64
+}
+
+// Physical memory not yet handed out to any frame, in bytes.
+pub fn free_memory() -> u64 {
+    memory_size() - used_memory()
 }
 
 pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
     VirtAddr::new(addr.as_u64() + unsafe { PHYS_MEM_OFFSET })
 }
 
+// Virtual address at which all physical memory is mapped; valid in every address space
+// (each process's PML4 clones the kernel's entries), so it can be used to reach a page
+// table that isn't the currently active one.
+pub fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(unsafe { PHYS_MEM_OFFSET })
+}
+
 pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
-    let mapper = unsafe { mapper(VirtAddr::new(PHYS_MEM_OFFSET)) };
-    mapper.translate_addr(addr)
+    MAPPER.lock().as_ref().expect("mapper not initialized").translate_addr(addr)
+}
+
+/// Repoint the shared `MAPPER` at `frame` (the level-4 table just loaded into CR3).
+///
+/// `map`/`map_next`/`unmap`/`virt_to_phys` all read and grow mappings through `MAPPER`;
+/// without this, they'd keep operating on the previous address space's PML4 after a
+/// `process::switch`, silently diverging from whatever CR3 actually points at.
+///
+/// # Safety
+/// `frame` must hold a valid, currently-active level-4 page table.
+pub unsafe fn activate_page_table(frame: PhysFrame) {
+    let level_4_table: &'static mut PageTable = &mut *phys_to_virt(frame.start_address()).as_mut_ptr();
+    *MAPPER.lock() = Some(OffsetPageTable::new(level_4_table, phys_mem_offset()));
+}
+
+// Pull a single frame from the shared frame allocator without mapping it, e.g. to back
+// a freshly allocated page table.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut().expect("frame allocator not initialized").allocate_frame()
+}
+
+// Map `page` to a specific `frame`, pulling the scratch frames the page table itself
+// needs from the shared frame allocator.
+pub fn map(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("mapper not initialized");
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().expect("frame allocator not initialized");
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+}
+
+// Map `page` to a fresh frame pulled from the shared frame allocator.
+//
+// Locks `MAPPER` before `FRAME_ALLOCATOR`, same order as `map`/`unmap`, so concurrent
+// callers can't deadlock on the two statics.
+pub fn map_next(page: Page, flags: PageTableFlags) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("mapper not initialized");
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().expect("frame allocator not initialized");
+    let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+}
+
+// Unmap `page` and return its backing frame to the shared frame allocator.
+pub fn unmap(page: Page) -> Result<(), UnmapError> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("mapper not initialized");
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().expect("frame allocator not initialized");
+    unsafe { frame_allocator.deallocate_frame(frame); }
+    Ok(())
 }
 
 pub unsafe fn mapper(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
@@ -50,29 +149,94 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
+// `BootInfoFrameAllocator::init` runs before `allocator::init_heap` (it's what maps the
+// heap in the first place), so it must not touch the global allocator. Usable ranges and
+// the free list are therefore plain fixed-capacity arrays, never `alloc::vec::Vec`.
+const MAX_USABLE_RANGES: usize = 64;
+const MAX_FREE_FRAMES: usize = 512;
+
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+    usable_ranges: [(u64, u64); MAX_USABLE_RANGES],
+    usable_range_count: usize,
+    range_index: usize,
+    next_addr: u64,
+    free_list: [PhysFrame; MAX_FREE_FRAMES],
+    free_count: usize,
+    frame_count: AtomicUsize,
 }
 
 impl BootInfoFrameAllocator {
+    // Precompute the usable `start..end` ranges once so `allocate_frame` never has to
+    // re-filter and re-walk the memory map; it only ever advances a cursor or pops the
+    // free list. Allocation-free: the memory map typically holds a handful of usable
+    // regions, well under `MAX_USABLE_RANGES`; any excess is simply not tracked.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator { memory_map, next: 0 }
+        let mut usable_ranges = [(0u64, 0u64); MAX_USABLE_RANGES];
+        let mut usable_range_count = 0;
+        for region in memory_map.iter() {
+            if region.region_type != MemoryRegionType::Usable {
+                continue;
+            }
+            if usable_range_count >= MAX_USABLE_RANGES {
+                break;
+            }
+            usable_ranges[usable_range_count] = (region.range.start_addr(), region.range.end_addr());
+            usable_range_count += 1;
+        }
+        let next_addr = if usable_range_count > 0 { usable_ranges[0].0 } else { 0 };
+        BootInfoFrameAllocator {
+            usable_ranges,
+            usable_range_count,
+            range_index: 0,
+            next_addr,
+            free_list: [PhysFrame::containing_address(PhysAddr::new(0)); MAX_FREE_FRAMES],
+            free_count: 0,
+            frame_count: AtomicUsize::new(0),
+        }
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    // Number of physical frames currently handed out (free-list frames don't count).
+    pub fn frame_count(&self) -> usize {
+        self.frame_count.load(Ordering::SeqCst)
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        if self.free_count > 0 {
+            self.free_count -= 1;
+            let frame = self.free_list[self.free_count];
+            self.frame_count.fetch_add(1, Ordering::SeqCst);
+            return Some(frame);
+        }
+        while self.range_index < self.usable_range_count {
+            let (_, end) = self.usable_ranges[self.range_index];
+            if self.next_addr < end {
+                let frame = PhysFrame::containing_address(PhysAddr::new(self.next_addr));
+                self.next_addr += 4096;
+                self.frame_count.fetch_add(1, Ordering::SeqCst);
+                return Some(frame);
+            }
+            self.range_index += 1;
+            self.next_addr = if self.range_index < self.usable_range_count {
+                self.usable_ranges[self.range_index].0
+            } else {
+                0
+            };
+        }
+        None
+    }
+}
+
+unsafe impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        // Capacity is generous for a kernel this size; if it's ever exhausted the frame
+        // is simply not reclaimed rather than growing a heap-backed list (which would be
+        // unsound this early in boot, before the heap exists).
+        if self.free_count < MAX_FREE_FRAMES {
+            self.free_list[self.free_count] = frame;
+            self.free_count += 1;
+            self.frame_count.fetch_sub(1, Ordering::SeqCst);
+        }
     }
 }
@@ -3,16 +3,50 @@ use alloc::string::{String, ToString};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::memory;
 
 lazy_static! {
     pub static ref PIDS: AtomicUsize = AtomicUsize::new(0);
-    pub static ref PROCESS: Mutex<Process> = Mutex::new(Process::new("/"));
+    static ref PROCESS_TABLE: Mutex<BTreeMap<usize, Process>> = {
+        let mut table = BTreeMap::new();
+        let init = Process::new("/");
+        table.insert(init.id, init);
+        Mutex::new(table)
+    };
 }
 
+// PID of the process currently executing; `id()`/`env()`/`dir()`/... all resolve
+// against this entry in `PROCESS_TABLE`.
+static CURRENT_PID: AtomicUsize = AtomicUsize::new(0);
+
+// Forwards to the shared frame allocator; `Mapper::map_to` needs one in scope to create
+// any intermediate (L3/L2/L1) page table frames that don't exist yet.
+struct ProcessFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for ProcessFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        memory::allocate_frame()
+    }
+}
+
+// Fixed virtual addresses reserved per address space, analogous to the bootloader's
+// `kernel-stack-address`/`boot-info-address` layout. Neither may collide with an
+// existing kernel mapping (e.g. `allocator::HEAP_START`) or with each other; this is
+// checked (not just assumed) in `create_page_table`, since the PML4 is shared below the
+// top level and a silent collision would corrupt every other address space.
+pub const STACK_ADDRESS: u64 = 0x5555_5555_0000;
+pub const STACK_SIZE: u64 = 4 * 4096; // 16 KiB; the stack grows down from the top of this range.
+pub const BOOT_INFO_ADDRESS: u64 = 0x6666_6666_0000;
+
 pub struct Process {
     id: usize,
     env: BTreeMap<String, String>,
     dir: String,
+    page_table_frame: Option<PhysFrame>,
 }
 
 impl Process {
@@ -20,32 +54,157 @@ impl Process {
         let id = PIDS.fetch_add(1, Ordering::SeqCst);
         let env = BTreeMap::new();
         let dir = dir.to_string();
-        Self { id, env, dir }
+        Self { id, env, dir, page_table_frame: None }
+    }
+
+    // Allocate this process's own PML4 frame, seed it with every one of the kernel's
+    // live mappings so the kernel (and its heap) stays mapped in every address space,
+    // then map in this process's private stack and boot-info pages.
+    //
+    // Unlike a classic higher-half kernel, this kernel's own mappings (identity-mapped
+    // code near physical address 0, the heap at `allocator::HEAP_START`, ...) are not
+    // confined to PML4 indices 256..512 — some sit well below that. So the *entire*
+    // level-4 table is cloned rather than just its higher half: any index already
+    // present in the kernel table keeps pointing at the same (shared) lower-level
+    // tables, while indices the kernel never used stay not-present and are free for
+    // this process's own mappings.
+    pub fn create_page_table(&mut self) -> PhysFrame {
+        let frame = memory::allocate_frame().expect("process page table allocation failed");
+
+        let table: &mut PageTable = unsafe { &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr() };
+        table.zero();
+
+        // The kernel's own PML4, captured on the first call: `switch` only loads a table
+        // after creating it, so that first call still runs on the boot-time CR3. Later
+        // calls may run while another process's PML4 is active, and that table's private
+        // entries must not be cloned.
+        static KERNEL_PML4: Mutex<Option<PhysFrame>> = Mutex::new(None);
+        let kernel_frame = *KERNEL_PML4.lock().get_or_insert_with(|| Cr3::read().0);
+        let kernel_table: &PageTable = unsafe { &*memory::phys_to_virt(kernel_frame.start_address()).as_ptr() };
+        for i in 0..512 {
+            table[i] = kernel_table[i].clone();
+        }
+
+        let stack_start = Page::containing_address(VirtAddr::new(STACK_ADDRESS));
+        let stack_end = Page::containing_address(VirtAddr::new(STACK_ADDRESS + STACK_SIZE - 1));
+        let boot_info_page = Page::containing_address(VirtAddr::new(BOOT_INFO_ADDRESS));
+
+        // Cloning the whole PML4 shares every *existing* L3/L2/L1 table with the kernel
+        // and every other process. That's fine for indices the kernel already uses, but
+        // if a private region's PML4 slot were also already present, `map_to` would
+        // silently map straight into those shared lower-level tables instead of
+        // isolated ones. Fail loudly instead of corrupting shared page tables.
+        for page in [stack_start, stack_end, boot_info_page] {
+            let index = page.p4_index();
+            assert!(
+                table[index].is_unused(),
+                "process private region at {:?} collides with an existing kernel PML4 entry",
+                page.start_address(),
+            );
+        }
+
+        // The table isn't active yet (CR3 still points at another PML4), but
+        // its frames are reachable through the physical-memory mapping shared by every
+        // address space, so we can map into it without switching to it first.
+        let mut table_mapper = unsafe { OffsetPageTable::new(table, memory::phys_mem_offset()) };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for page in Page::range_inclusive(stack_start, stack_end).chain(Page::range_inclusive(boot_info_page, boot_info_page)) {
+            let page_frame = memory::allocate_frame().expect("process page allocation failed");
+            unsafe {
+                table_mapper
+                    .map_to(page, page_frame, flags, &mut ProcessFrameAllocator)
+                    .expect("failed to map process page")
+                    .ignore(); // table isn't active, nothing to flush yet
+            }
+        }
+
+        self.page_table_frame = Some(frame);
+        frame
+    }
+
+    // Top of the per-process stack range: since the stack grows down, this (not the
+    // range's base address) is what a consumer should load into RSP.
+    pub fn stack_address(&self) -> VirtAddr {
+        VirtAddr::new(STACK_ADDRESS + STACK_SIZE)
     }
+
+    pub fn boot_info_address(&self) -> VirtAddr {
+        VirtAddr::new(BOOT_INFO_ADDRESS)
+    }
+}
+
+// Activate `pid`'s address space by loading its PML4 frame into CR3, creating it first
+// if this is the process's first switch, and flushing the TLB.
+pub fn switch(pid: usize) {
+    let mut table = PROCESS_TABLE.lock();
+    let process = match table.get_mut(&pid) {
+        Some(process) => process,
+        None => return,
+    };
+    let frame = match process.page_table_frame {
+        Some(frame) => frame,
+        None => process.create_page_table(),
+    };
+    unsafe {
+        Cr3::write(frame, Cr3Flags::empty());
+        // Repoint the shared mapper at the table CR3 now points to, so map()/map_next()/
+        // unmap() keep growing the address space that's actually active.
+        memory::activate_page_table(frame);
+    }
+    CURRENT_PID.store(pid, Ordering::SeqCst);
+}
+
+// Create a child of the current process, inheriting its environment and working
+// directory, and return the new PID.
+pub fn spawn() -> usize {
+    let mut table = PROCESS_TABLE.lock();
+    let (env, dir) = table.get(&current_pid()).map_or_else(
+        || (BTreeMap::new(), String::from("/")),
+        |parent| (parent.env.clone(), parent.dir.clone()),
+    );
+
+    let mut child = Process::new(&dir);
+    child.env = env;
+    let pid = child.id;
+    table.insert(pid, child);
+    pid
+}
+
+// Remove `pid` from the process table.
+pub fn exit(pid: usize) {
+    // TODO: free the process's page table frame (and its mapped pages) once a
+    // recursive-unmap helper exists.
+    PROCESS_TABLE.lock().remove(&pid);
+}
+
+fn current_pid() -> usize {
+    CURRENT_PID.load(Ordering::SeqCst)
 }
 
 pub fn id() -> usize {
-    PROCESS.lock().id
+    current_pid()
 }
 
 pub fn env(key: &str) -> Option<String> {
-    match PROCESS.lock().env.get(key.into()) {
-        Some(val) => Some(val.clone()),
-        None => None,
-    }
+    PROCESS_TABLE.lock().get(&current_pid())?.env.get(key).cloned()
 }
 
 pub fn envs() -> BTreeMap<String, String> {
-    PROCESS.lock().env.clone()
+    PROCESS_TABLE.lock().get(&current_pid()).map_or_else(BTreeMap::new, |p| p.env.clone())
 }
 
 pub fn dir() -> String {
-    PROCESS.lock().dir.clone()
+    PROCESS_TABLE.lock().get(&current_pid()).map_or_else(String::new, |p| p.dir.clone())
 }
+
 pub fn set_env(key: &str, val: &str) {
-    PROCESS.lock().env.insert(key.into(), val.into());
+    if let Some(process) = PROCESS_TABLE.lock().get_mut(&current_pid()) {
+        process.env.insert(key.into(), val.into());
+    }
 }
 
 pub fn set_dir(dir: &str) {
-    PROCESS.lock().dir = dir.into();
+    if let Some(process) = PROCESS_TABLE.lock().get_mut(&current_pid()) {
+        process.dir = dir.into();
+    }
 }
\ No newline at end of file